@@ -1,9 +1,13 @@
-#![feature(proc_macro_hygiene)]
+// diesel 1.4's `table!`/`Insertable`/`Queryable` derives expand to impls that trip the
+// `non_local_definitions` lint on current rustc; there's no fix short of a diesel major bump.
+#![allow(non_local_definitions)]
 
 extern crate hyper;
 extern crate maud;
 extern crate futures;
 extern crate url;
+extern crate r2d2;
+extern crate r2d2_diesel;
 
 #[macro_use]
 extern crate serde_json;
@@ -19,14 +23,11 @@ extern crate log;
 extern crate env_logger;
 
 use std::collections::HashMap;
-use std::error::Error;
 use std::env;
-use std::io;
 
-use hyper::{Chunk, StatusCode};
-use hyper::Method::{Get, Post};
-use hyper::server::{Request, Response, Service};
-use hyper::header::{ContentLength, ContentType};
+use hyper::{Body, Chunk, Method, Request, Response, Server, StatusCode};
+use hyper::service::{NewService, Service};
+use hyper::header::{ACCEPT, CONTENT_LENGTH, CONTENT_TYPE, VARY};
 
 use futures::Stream;
 use futures::future::{Future, FutureResult};
@@ -34,6 +35,9 @@ use futures::future::{Future, FutureResult};
 use diesel::prelude::*;
 use diesel::pg::PgConnection;
 
+use r2d2::{Pool, PooledConnection};
+use r2d2_diesel::ConnectionManager;
+
 use maud::html;
 
 mod models;
@@ -41,37 +45,91 @@ mod schema;
 
 use models::{Message, NewMessage};
 
-const DEFAULT_DATABASE_URL: &str = env::var("DATABASE_URL").expect("DATABASE_URL is not set!");  // Reads DATABASE_URL value from .env file
+const DEFAULT_DATABASE_URL: &str = "postgres://postgres@localhost/microservice";  // Used when DATABASE_URL isn't set in the environment
+const DEFAULT_DATABASE_POOL_SIZE: u32 = 10;  // Used when DATABASE_POOL_SIZE is not set
+
+type PgPool = Pool<ConnectionManager<PgConnection>>;
 
-struct Microservice;
+struct Microservice {
+    pool: PgPool,
+}
 
 struct TimeRange {
     before: Option<i64>,
     after: Option<i64>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    contains: Option<String>,
+}
+
+const DEFAULT_MESSAGE_LIMIT: i64 = 50;  // Used when the caller doesn't pass `limit`
+const MAX_MESSAGE_LIMIT: i64 = 200;  // Caps `limit` so a caller can't force an unbounded load
+
+/// The two shapes `GET /` can render its messages as, chosen by the request's `Accept` header
+enum ResponseFormat {
+    Html,
+    Json,
+}
+
+fn response_format(request: &Request<Body>) -> ResponseFormat {
+    let prefers_json = request
+        .headers()
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.contains("application/json"))
+        .unwrap_or(false);
+
+    if prefers_json {
+        ResponseFormat::Json
+    } else {
+        ResponseFormat::Html
+    }
+}
+
+/// Distinguishes a fault caused by the caller (400) from one caused by us (500), so the
+/// response builder can pick the right status code instead of masquerading everything as a 500
+#[derive(Debug)]
+enum ServiceError {
+    BadRequest(String),
+    Internal(String),
+}
+
+impl ServiceError {
+    fn status_code(&self) -> StatusCode {
+        match *self {
+            ServiceError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ServiceError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn message(&self) -> &str {
+        match *self {
+            ServiceError::BadRequest(ref message) => message,
+            ServiceError::Internal(ref message) => message,
+        }
+    }
 }
 
-fn parse_form(form_chunk: Chunk) -> FutureResult<NewMessage, hyper::Error> {
-    /// Receives a Chunk (a message body), and parses out the username and message while handling errors appropriately
+fn parse_form(form_chunk: Chunk) -> FutureResult<NewMessage, ServiceError> {
+    // Receives a Chunk (a message body), and parses out the username and message while handling errors appropriately
     let mut form = url::form_urlencoded::parse(form_chunk.as_ref())  // Parse the form
         .into_owned()
         .collect::<HashMap<String, String>>();  // Parse the form into a HashMap
 
     if let Some(message) = form.remove("message") {  // Attempt to remove the message key from it
-        let username = form.remove("username").unwrap_or(String::from("anonymous"));  // Default username to "ananymous" if it's not there 
+        let username = form.remove("username").unwrap_or(String::from("anonymous"));  // Default username to "anonymous" if it's not there
         futures::future::ok(NewMessage { username, message })  // Return future containing our simple `NewMessage` struct
     } else {  // If attempt fails, return an error since a message is mandatory
-        futures::future::err(hyper::Error::from(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Missing field 'message",
-        )))
+        futures::future::err(ServiceError::BadRequest(String::from("Missing field 'message'")))
     }
 }
 
 fn write_to_db(
     new_message: NewMessage,
-    db_connection: &PgConnection,
-) -> FutureResult<i64, hyper::Error> {
+    db_connection: &PooledConnection<ConnectionManager<PgConnection>>,
+) -> FutureResult<i64, ServiceError> {
     use schema::messages;
+    let db_connection: &PgConnection = db_connection;  // Deref the pooled handle to the connection diesel expects
     let timestamp = diesel::insert_into(messages::table)
         .values(&new_message)
         .returning(messages::timestamp)
@@ -80,33 +138,32 @@ fn write_to_db(
     match timestamp {
         Ok(timestamp) => futures::future::ok(timestamp),
         Err(error) => {
-            error!("Error writing to database: {}", error.description());
-            futures::future::err(hyper::Error::from(
-                io::Error::new(io::ErrorKind::Other, "service error"),
-            ))
+            error!("Error writing to database: {}", error);
+            futures::future::err(ServiceError::Internal(String::from("service error")))
         }
     }
 }
 
 
-fn make_error_response(error_message: &str) -> FutureResult<hyper::Response, hyper::Error> {
+fn make_error_response(error: &ServiceError) -> FutureResult<Response<Body>, hyper::Error> {
     let payload = json!({
-        "error": error_message
+        "error": error.message()
     }).to_string();
     // When constructing a response struct, we need to set correct HTTP headers
-    let response = Response::new()
-        .with_status(StatusCode::InternalServerError)  // Set the HTTP status of the response to InternalServiceError (status 500)
-        .with_header(ContentLength(payload.len() as u64))  // Set the Content-Length header to the length of the response body
-        .with_header(ContentType::json())  // Set the Content-Type header to application/json
-        .with_body(payload);
+    let response = Response::builder()
+        .status(error.status_code())  // 400 for a caller mistake, 500 for a fault on our end
+        .header(CONTENT_LENGTH, payload.len() as u64)  // Set the Content-Length header to the length of the response body
+        .header(CONTENT_TYPE, "application/json")  // Set the Content-Type header to application/json
+        .body(Body::from(payload))
+        .unwrap();
     debug!("{:?}", response);
     futures::future::ok(response)
 }
 
 fn make_post_response(
-    result: Result<i64, hyper::Error>,
-) -> FutureResult<hyper::Response, hyper::Error> {
-    /// Return a response back to whoever blessed our microservice with a request
+    result: Result<i64, ServiceError>,
+) -> FutureResult<Response<Body>, hyper::Error> {
+    // Return a response back to whoever blessed our microservice with a request
     match result {  // Match on the `result` to see if we were able to write to database
         Ok(timestamp) => {
             // Create a JSON payload forming the body of the response we return
@@ -114,22 +171,23 @@ fn make_post_response(
                 "timestamp": timestamp
             }).to_string();
             // When constructing a response struct, we need to set correct HTTP headers
-            let response = Response::new()
-                // .with_header(StatusCode::Ok)  // Default status is OK(200), therefore we don't need to set it 
-                .with_header(ContentLength(payload.len() as u64))  // Set the Content-Length header to the length of the response body
-                .with_header(ContentType::json())  // Set the Content-Type header to application/json
-                .with_body(payload);
+            let response = Response::builder()
+                // Default status is OK(200), therefore we don't need to set it
+                .header(CONTENT_LENGTH, payload.len() as u64)  // Set the Content-Length header to the length of the response body
+                .header(CONTENT_TYPE, "application/json")  // Set the Content-Type header to application/json
+                .body(Body::from(payload))
+                .unwrap();
             debug!("{:?}", response);
             futures::future::ok(response)
         }
         // Refactored out the code to make a response struct for erroneous case
-        Err(error) => make_error_response(error.description()),
+        Err(error) => make_error_response(&error),
     }
 }
 
-fn parse_query(query: &str) -> Result<TimeRange, String> {
+fn parse_query(query: &str) -> Result<TimeRange, ServiceError> {
     // Parse the form into a hashmap, since the syntax is still `key=value&key=value`
-    let args = url::form_urlencoded::parse(&query.as_bytes())
+    let args = url::form_urlencoded::parse(query.as_bytes())
         .into_owned()
         .collect::<HashMap<String, String>>();
 
@@ -137,43 +195,94 @@ fn parse_query(query: &str) -> Result<TimeRange, String> {
     // If there, parse to i64
     let before = args.get("before").map(|value| value.parse::<i64>());
     if let Some(Err(ref error)) = before {
-        return Err(format!("Error parsing 'before': {}", error));
+        return Err(ServiceError::BadRequest(format!("Error parsing 'before': {}", error)));
     }
 
     // Try to get `after` field from the form
     // If there, parse to i64
     let after = args.get("after").map(|value| value.parse::<i64>());
     if let Some(Err(ref error)) = after {
-        return Err(format!("Error parsing 'after': {}", error));
+        return Err(ServiceError::BadRequest(format!("Error parsing 'after': {}", error)));
+    }
+
+    // Try to get `limit` field from the form
+    // If there, parse to i64
+    let limit = args.get("limit").map(|value| value.parse::<i64>());
+    if let Some(Err(ref error)) = limit {
+        return Err(ServiceError::BadRequest(format!("Error parsing 'limit': {}", error)));
+    }
+    if let Some(Ok(limit)) = limit {
+        if limit < 0 {
+            return Err(ServiceError::BadRequest(String::from("'limit' must not be negative")));
+        }
+    }
+
+    // Try to get `offset` field from the form
+    // If there, parse to i64
+    let offset = args.get("offset").map(|value| value.parse::<i64>());
+    if let Some(Err(ref error)) = offset {
+        return Err(ServiceError::BadRequest(format!("Error parsing 'offset': {}", error)));
     }
-    
+    if let Some(Ok(offset)) = offset {
+        if offset < 0 {
+            return Err(ServiceError::BadRequest(String::from("'offset' must not be negative")));
+        }
+    }
+
+    // `contains` is a plain substring to search for, no parsing needed
+    let contains = args.get("contains").cloned();
+
     Ok(TimeRange {
         before: before.map(|b| b.unwrap()),
         after: after.map(|a| a.unwrap()),
+        limit: limit.map(|l| l.unwrap()),
+        offset: offset.map(|o| o.unwrap()),
+        contains,
     })
 }
 
-fn query_db(time_range: TimeRange, db_connection: &PgConnection) -> Option<Vec<Message>> {
+fn query_db(
+    time_range: TimeRange,
+    db_connection: &PooledConnection<ConnectionManager<PgConnection>>,
+) -> Result<Vec<Message>, ServiceError> {
     use schema::messages;
-    let TimeRange { before, after } = time_range;
+    let db_connection: &PgConnection = db_connection;  // Deref the pooled handle to the connection diesel expects
+    let TimeRange { before, after, limit, offset, contains } = time_range;
 
     let mut query = messages::table.into_boxed();
 
     if let Some(before) = before {
-        query = query.filter(messages::timestamp.lt(before as i64))
+        query = query.filter(messages::timestamp.lt(before))
     }
 
     if let Some(after) = after {
-        query = query.filter(messages::timestamp.gt(after as i64))
+        query = query.filter(messages::timestamp.gt(after))
+    }
+
+    if let Some(pattern) = contains {
+        // Escape ILIKE's own wildcards so a literal '%' or '_' in the search term isn't
+        // interpreted as a pattern match
+        let escaped = pattern
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        query = query.filter(messages::message.ilike(format!("%{}%", escaped)))
+    }
+
+    let limit = limit.unwrap_or(DEFAULT_MESSAGE_LIMIT).min(MAX_MESSAGE_LIMIT);
+    query = query.limit(limit);
+
+    if let Some(offset) = offset {
+        query = query.offset(offset)
     }
 
     let query_result = query.load::<Message>(db_connection);
 
     match query_result {
-        Ok(result) => Some(result),
+        Ok(result) => Ok(result),
         Err(error) => {
             error!("Error querying DB: {}", error);
-            None
+            Err(ServiceError::Internal(String::from("service error")))
         }
     }
 }
@@ -197,56 +306,83 @@ fn render_page(messages: Vec<Message>) -> String {
 }
 
 fn make_get_response(
-    messages: Option<Vec<Message>>,
-) -> FutureResult<hyper::Response, hyper::Error> {
-    let response = match messages {
-        Some(messages) => {  // If the messages option contains a value
+    messages: Vec<Message>,
+    format: ResponseFormat,
+) -> FutureResult<Response<Body>, hyper::Error> {
+    // Render it as the format the caller asked for
+    let response = match format {
+        ResponseFormat::Json => {
+            let payload = serde_json::to_string(&messages).unwrap();
+            Response::builder()
+                .header(CONTENT_LENGTH, payload.len() as u64)
+                .header(CONTENT_TYPE, "application/json")
+                .header(VARY, "accept")  // Body shape depends on the Accept header; don't let caches mix them up
+                .body(Body::from(payload))
+                .unwrap()
+        }
+        ResponseFormat::Html => {
             let body = render_page(messages);  // Pass the messages on to render_page, which will return an HTML page that forms the body of our response,
-            Response::new()
-                .with_header(ContentLength(body.len() as u64))
-                .with_header(ContentType::html())
-                .with_body(body)
+            Response::builder()
+                .header(CONTENT_LENGTH, body.len() as u64)
+                .header(CONTENT_TYPE, "text/html")
+                .header(VARY, "accept")  // Body shape depends on the Accept header; don't let caches mix them up
+                .body(Body::from(body))
+                .unwrap()
         }
-        None => Response::new().with_status(StatusCode::InternalServerError),
     };
     debug!("{:?}", response);
     futures::future::ok(response)
 }
 
-fn connect_to_db() -> Option<PgConnection> {
+fn build_pool() -> PgPool {
     let database_url = env::var("DATABASE_URL").unwrap_or(String::from(DEFAULT_DATABASE_URL));
-    match PgConnection::establish(&database_url) {
-        Ok(connection) => Some(connection),
-        Err(error) => {
-            error!("Error connecting to database: {}", error.description());
-            None
-        }
-    }
+    let pool_size = env::var("DATABASE_POOL_SIZE")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_DATABASE_POOL_SIZE);
+    let manager = ConnectionManager::<PgConnection>::new(database_url);
+    Pool::builder()
+        .max_size(pool_size)
+        .build(manager)
+        .expect("Failed to create database connection pool")
+}
+
+fn checkout_connection(
+    pool: &PgPool,
+) -> Result<PooledConnection<ConnectionManager<PgConnection>>, ServiceError> {
+    pool.get().map_err(|error| {
+        let error = ServiceError::Internal(format!(
+            "Error checking out database connection: {}",
+            error
+        ));
+        error!("{}", error.message());
+        error
+    })
 }
 
 impl Service for Microservice {  // Basic types for our service
-    type Request = Request;  // 
-    type Response = Response;
+    type ReqBody = Body;
+    type ResBody = Body;
     type Error = hyper::Error;
-    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;  // Future type is boxed because it is a trait
+    type Future = Box<dyn Future<Item = Response<Self::ResBody>, Error = Self::Error> + Send>;  // Future type is boxed because it is a trait
 
-    fn call(&self, request: Request) -> Self::Future { . // hyper::Request is an object representing a parsed HTTP request
+    fn call(&mut self, request: Request<Body>) -> Self::Future {  // hyper::Request is an object representing a parsed HTTP request
         debug!("{:?}", request);
-        let db_connection = match connect_to_db() {
-            Some(connection) => connection,
-            None => {
-                return Box::new(futures::future::ok(
-                    Response::new().with_status(StatusCode::InternalServerError),
-                ))
-            }
-        };
-        // Distinguish between different requests by matching on the method and path of the request
-        match (request.method(), request.path()) {
+        // Distinguish between different requests by matching on the method and path of the request.
+        // Connection checkout happens per matched route below, so an unmatched route (404) never
+        // has to hold a pooled connection it doesn't use.
+        match (request.method(), request.uri().path()) {
             // Accept POST requests to our service’s root path ("/") and expect them to contain a username and message field in their form data.
-            (&Post, "/") => {
+            (&Method::POST, "/") => {
+                let db_connection = match checkout_connection(&self.pool) {
+                    Ok(connection) => connection,
+                    Err(error) => return Box::new(make_error_response(&error)),
+                };
                 let future = request
-                    .body()
+                    .into_body()
                     .concat2()
+                    // A body-read failure is on us, not the caller
+                    .map_err(|error| ServiceError::Internal(error.to_string()))
                     // `and_then` combinator will call a function with the value contained in a future
                     .and_then(parse_form)  // Returns a new future
                     // and then pass that information on to a function that writes the values of those fields into a database
@@ -256,41 +392,125 @@ impl Service for Microservice {  // Basic types for our service
                 Box::new(future)  // Return a response
             }
             // Sent to our server to fetch messages
-            (&Get, "/") => { 
+            (&Method::GET, "/") => {
+                let db_connection = match checkout_connection(&self.pool) {
+                    Ok(connection) => connection,
+                    Err(error) => return Box::new(make_error_response(&error)),
+                };
+                // Caller may ask for the feed as JSON via `Accept`; default to the HTML page
+                let format = response_format(&request);
                 // Request is allowed to have two query arguments, `before` and `after`, both timestamps to constrain
                 // the messages fetched according to their timestamp, and both are optional
-                let time_range = match request.query() {  // `request.query()` returns an `Option<&str>, since a URI may not have a query string at all
+                let time_range = match request.uri().query() {  // `uri().query()` returns an `Option<&str>, since a URI may not have a query string at all
                     // If a query string is present, call `parse_query`, which parses the arguments and returns a TimeRange struct
                     Some(query) => parse_query(query),
                     // If query string is not present, create a TimeRange with values as None
                     None => Ok(TimeRange {
                         before: None,
                         after: None,
-                    }), 
+                        limit: None,
+                        offset: None,
+                        contains: None,
+                    }),
                 };
                 let response = match time_range {
                     // Fetch the messages for us, and `make_get_response`, which creates an appropriate Response object to return back to the client
-                    Ok(time_range) => make_get_response(query_db(time_range, &db_connection)),
+                    Ok(time_range) => match query_db(time_range, &db_connection) {
+                        Ok(messages) => make_get_response(messages, format),
+                        Err(error) => make_error_response(&error),
+                    },
                     // Timestamps may be invalid (e.g. not numeric), so we have to deal with the case where parsing their values fails
-                    // In such a case, parse_query will return an error message, which we can forward to `make_error_response`
-                    Err(error) => make_error_response(&error),  // 
+                    // In such a case, parse_query will return a `ServiceError::BadRequest`, which we can forward to `make_error_response`
+                    Err(error) => make_error_response(&error),
                 };
                 Box::new(response)
             }
             _ => Box::new(futures::future::ok(
-                Response::new().with_status(StatusCode::NotFound),
+                Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty())
+                    .unwrap(),
             )),
         }
     }
 }
 
+// Factory handed to `.serve()`; hyper calls `new_service` once per incoming connection so that
+// each `Microservice` gets its own (cloned) handle into the shared pool.
+struct MicroserviceFactory {
+    pool: PgPool,
+}
+
+impl NewService for MicroserviceFactory {
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Service = Microservice;
+    type Future = FutureResult<Self::Service, Self::InitError>;
+    type InitError = hyper::Error;
+
+    fn new_service(&self) -> Self::Future {
+        futures::future::ok(Microservice { pool: self.pool.clone() })
+    }
+}
+
 fn main() {
     env_logger::init();
-    let address = "127.0.0.1:8080".parse().unwrap(); 
-    // New instance is created for each new request
-    let server = hyper::server::Http::new()  // Binding IP address to an Http instance
-        .bind(&address, move || Ok(Microservice))
-        .unwrap();
+    let address = "127.0.0.1:8080".parse().unwrap();
+    let pool = build_pool();  // Built once and shared across every connection the server handles
+    let server = Server::bind(&address)
+        .serve(MicroserviceFactory { pool })
+        .map_err(|error| error!("server error: {}", error));
     info!("Running microservice at {}", address);
-    server.run().unwrap();  // Start the server
+    hyper::rt::run(server);  // Start the server
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_code_maps_bad_request_to_400() {
+        let error = ServiceError::BadRequest(String::from("bad"));
+        assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn status_code_maps_internal_to_500() {
+        let error = ServiceError::Internal(String::from("bad"));
+        assert_eq!(error.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn parse_query_rejects_non_numeric_before() {
+        let result = parse_query("before=not-a-number");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn parse_query_rejects_negative_limit() {
+        let result = parse_query("limit=-1");
+        match result {
+            Err(ServiceError::BadRequest(_)) => {}
+            Err(ServiceError::Internal(message)) => panic!("expected BadRequest, got Internal: {}", message),
+            Ok(_) => panic!("expected BadRequest, got Ok"),
+        }
+    }
+
+    #[test]
+    fn parse_query_rejects_negative_offset() {
+        let result = parse_query("offset=-1");
+        match result {
+            Err(ServiceError::BadRequest(_)) => {}
+            Err(ServiceError::Internal(message)) => panic!("expected BadRequest, got Internal: {}", message),
+            Ok(_) => panic!("expected BadRequest, got Ok"),
+        }
+    }
+
+    #[test]
+    fn parse_query_accepts_valid_limit_and_offset() {
+        let time_range = parse_query("limit=10&offset=5").unwrap();
+        assert_eq!(time_range.limit, Some(10));
+        assert_eq!(time_range.offset, Some(5));
+    }
 }
@@ -0,0 +1,8 @@
+table! {
+    messages (id) {
+        id -> Int4,
+        username -> Varchar,
+        message -> Varchar,
+        timestamp -> Int8,
+    }
+}